@@ -1,6 +1,7 @@
 use crate::storage_layer::JsonStorage;
 use serde::ser::{SerializeMap, Serializer};
 use serde_json::Value;
+use std::cell::RefCell;
 use std::io::Write;
 use tracing::{Event, Subscriber};
 use tracing_core::metadata::Level;
@@ -9,7 +10,30 @@ use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::Layer;
 
-const RESERVED_FIELDS: [&str; 3] = ["msg", "level", "time"];
+thread_local! {
+    /// Reused across events on the same thread to avoid a fresh heap allocation for every
+    /// formatted record: it is cleared, not freed, before each record is formatted into it.
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// The format used to render the `time` field of a Bunyan record.
+#[derive(Clone, Debug)]
+pub enum TimestampFormat {
+    /// RFC3339-formatted string, e.g. `2021-01-01T12:00:00.123+00:00`. This is the Bunyan default.
+    Rfc3339,
+    /// Seconds since the Unix epoch, emitted as a JSON number.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch, emitted as a JSON number.
+    UnixMillis,
+    /// A custom [`chrono` strftime pattern](https://docs.rs/chrono/latest/chrono/format/strftime/index.html).
+    Custom(String),
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::Rfc3339
+    }
+}
 
 /// Convert from log levels to Bunyan's levels.
 fn format_log_level(level: &Level) -> &'static str {
@@ -22,11 +46,103 @@ fn format_log_level(level: &Level) -> &'static str {
     }
 }
 
+/// Convert from log levels to the numeric level codes used by the [Bunyan spec](https://github.com/trentm/node-bunyan#levels).
+///
+/// `tracing` has no equivalent of Bunyan's `FATAL`, so it is never produced here.
+fn bunyan_level_code(level: &Level) -> u16 {
+    match level.as_log() {
+        log::Level::Error => 50,
+        log::Level::Warn => 40,
+        log::Level::Info => 30,
+        log::Level::Debug => 20,
+        log::Level::Trace => 10,
+    }
+}
+
+/// Resolve the local hostname once, falling back to an empty string if it cannot be determined.
+///
+/// Calls the platform `gethostname` directly instead of depending on a crate for this: the
+/// binding it would need is a single POSIX syscall, and all types involved (`c_char`, `c_int`)
+/// already live in `std::os::raw`.
+fn get_hostname() -> String {
+    #[cfg(unix)]
+    {
+        use std::os::raw::{c_char, c_int};
+
+        extern "C" {
+            fn gethostname(name: *mut c_char, len: usize) -> c_int;
+        }
+
+        let mut buffer = vec![0u8; 256];
+        let result = unsafe { gethostname(buffer.as_mut_ptr() as *mut c_char, buffer.len()) };
+        if result == 0 {
+            let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+            buffer.truncate(len);
+            if let Ok(hostname) = String::from_utf8(buffer) {
+                return hostname;
+            }
+        }
+    }
+    String::new()
+}
+
+/// Controls which span lifecycle events are turned into Bunyan log records, mirroring
+/// [`tracing_subscriber::fmt::format::FmtSpan`](https://docs.rs/tracing-subscriber/latest/tracing_subscriber/fmt/format/struct.FmtSpan.html).
+///
+/// Unlike `FmtSpan`, there are no `ENTER`/`EXIT` variants: a span can be entered and exited many
+/// times (e.g. across `.await` points) before it closes, and we only want one record per span
+/// creation and one per span closure, not one per enter/exit. `elapsed_milliseconds` on the
+/// `CLOSE` record is the total time between creation and closure, not active-only time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BunyanFmtSpan(u8);
+
+impl BunyanFmtSpan {
+    /// Do not emit any span lifecycle records. This is the default.
+    pub const NONE: BunyanFmtSpan = BunyanFmtSpan(0);
+    /// Emit a record when a span is created.
+    pub const NEW: BunyanFmtSpan = BunyanFmtSpan(1 << 0);
+    /// Emit a record, including `elapsed_milliseconds`, when a span closes.
+    pub const CLOSE: BunyanFmtSpan = BunyanFmtSpan(1 << 1);
+    /// Emit records for both span creation and span closure.
+    pub const FULL: BunyanFmtSpan = BunyanFmtSpan(Self::NEW.0 | Self::CLOSE.0);
+
+    fn contains(self, other: BunyanFmtSpan) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for BunyanFmtSpan {
+    fn default() -> Self {
+        BunyanFmtSpan::NONE
+    }
+}
+
+impl std::ops::BitOr for BunyanFmtSpan {
+    type Output = BunyanFmtSpan;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        BunyanFmtSpan(self.0 | rhs.0)
+    }
+}
+
+/// Stored in a span's extensions when it is created, so that we can compute how long it was
+/// open for once it closes.
+struct SpanTiming(std::time::Instant);
+
 /// This layer is exclusively concerned with formatting information using the [Bunyan format](https://github.com/trentm/node-bunyan).
 /// It relies on the upstream `JsonStorageLayer` to get access to the fields attached to
 /// each span.
 pub struct BunyanFormattingLayer<W: MakeWriter + 'static> {
     make_writer: W,
+    timestamp_format: TimestampFormat,
+    name: String,
+    pid: u32,
+    hostname: String,
+    use_bunyan_level_codes: bool,
+    span_events: BunyanFmtSpan,
+    message_field_name: String,
+    level_field_name: String,
+    time_field_name: String,
 }
 
 impl<W: MakeWriter + 'static> BunyanFormattingLayer<W> {
@@ -49,8 +165,161 @@ impl<W: MakeWriter + 'static> BunyanFormattingLayer<W> {
     ///
     /// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), || std::io::stdout());
     /// ```
-    pub fn new(make_writer: W) -> Self {
-        Self { make_writer }
+    pub fn new(name: String, make_writer: W) -> Self {
+        Self {
+            make_writer,
+            timestamp_format: TimestampFormat::default(),
+            name,
+            pid: std::process::id(),
+            hostname: get_hostname(),
+            use_bunyan_level_codes: false,
+            span_events: BunyanFmtSpan::default(),
+            message_field_name: "msg".into(),
+            level_field_name: "level".into(),
+            time_field_name: "time".into(),
+        }
+    }
+
+    /// Configure the format used to render the `time` field, overriding the default RFC3339
+    /// representation.
+    pub fn with_timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// When enabled, `level` is serialised as the numeric codes mandated by the Bunyan spec
+    /// (e.g. `30` for `INFO`) instead of the human-readable strings `tracing` uses by default.
+    pub fn with_bunyan_level_codes(mut self, use_bunyan_level_codes: bool) -> Self {
+        self.use_bunyan_level_codes = use_bunyan_level_codes;
+        self
+    }
+
+    /// Configure which span lifecycle events (`new`/`close`) are emitted as Bunyan log records.
+    /// Defaults to [`BunyanFmtSpan::NONE`].
+    pub fn with_span_events(mut self, span_events: BunyanFmtSpan) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Override the key used for the message field, overriding the Bunyan default of `msg`.
+    pub fn with_message_field_name<T: Into<String>>(mut self, message_field_name: T) -> Self {
+        self.message_field_name = message_field_name.into();
+        self
+    }
+
+    /// Override the key used for the level field, overriding the Bunyan default of `level`.
+    pub fn with_level_field_name<T: Into<String>>(mut self, level_field_name: T) -> Self {
+        self.level_field_name = level_field_name.into();
+        self
+    }
+
+    /// Override the key used for the timestamp field, overriding the Bunyan default of `time`.
+    pub fn with_time_field_name<T: Into<String>>(mut self, time_field_name: T) -> Self {
+        self.time_field_name = time_field_name.into();
+        self
+    }
+
+    /// Whether `key` collides with one of the core field names and should therefore be skipped
+    /// when serialising user- or span-supplied fields. This covers both the always-present
+    /// `v`/`name`/`hostname`/`pid` fields and the configurable message/level/time field names.
+    fn is_reserved_field(&self, key: &str) -> bool {
+        key == "v"
+            || key == "name"
+            || key == "hostname"
+            || key == "pid"
+            || key == self.message_field_name
+            || key == self.level_field_name
+            || key == self.time_field_name
+    }
+
+    /// Serialise the fields attached to `span` and all of its ancestors into `map_serializer`.
+    ///
+    /// `JsonStorageLayer` copies each parent's stored fields into a child span when it is
+    /// created, so a single span's storage already holds the full ancestry. We still walk
+    /// `scope().from_root()` defensively and collect into a map keyed by field name, rather than
+    /// serializing each ancestor's storage as we visit it, so that a field inherited at more than
+    /// one level is only ever written once (with the innermost value winning) instead of being
+    /// duplicated in the output.
+    fn serialize_span_ancestry_fields<S>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+    ) -> Result<(), serde_json::Error>
+    where
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let mut fields = std::collections::HashMap::new();
+        let mut reserved_keys_skipped = Vec::new();
+        for ancestor_span in span.scope().from_root() {
+            let extensions = ancestor_span.extensions();
+            if let Some(visitor) = extensions.get::<JsonStorage>() {
+                for (&key, value) in visitor.values() {
+                    if self.is_reserved_field(key) {
+                        reserved_keys_skipped.push(key);
+                        continue;
+                    }
+                    fields.insert(key, value.clone());
+                }
+            }
+        }
+        // Logged only once every `extensions()` borrow above has been released: `debug!` can be
+        // routed back through this very layer, which would otherwise try to re-acquire the same
+        // span's extensions while we are still holding it.
+        for key in reserved_keys_skipped {
+            tracing::debug!(
+                "{} is a reserved field in the bunyan log format. Skipping it.",
+                key
+            );
+        }
+        for (key, value) in fields {
+            map_serializer.serialize_entry(key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Build and emit a Bunyan record for a span lifecycle event (span creation or closure).
+    ///
+    /// Reuses the same thread-local buffer as `on_event` (see `BUFFER`) rather than allocating a
+    /// fresh `Vec` per record, for the same reasons: span lifecycle records are formatted on the
+    /// same hot path and should not bypass that optimisation.
+    fn emit_span_lifecycle_record<S>(
+        &self,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+        message: &str,
+        elapsed_milliseconds: Option<u128>,
+    ) where
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        // See `on_event` for why we take the buffer out of the `RefCell` instead of holding a
+        // `borrow_mut()` across formatting.
+        let mut buffer = BUFFER.with(|buffer| std::mem::take(&mut *buffer.borrow_mut()));
+        buffer.clear();
+
+        let format = |buffer: &mut Vec<u8>| -> Result<(), std::io::Error> {
+            let mut serializer = serde_json::Serializer::new(&mut *buffer);
+            let mut map_serializer = serializer.serialize_map(None)?;
+
+            self.serialize_bunyan_core_fields(
+                &mut map_serializer,
+                message,
+                span.metadata().level(),
+            )?;
+            map_serializer.serialize_entry("event", span.metadata().name())?;
+            if let Some(elapsed_milliseconds) = elapsed_milliseconds {
+                map_serializer.serialize_entry("elapsed_milliseconds", &elapsed_milliseconds)?;
+            }
+
+            self.serialize_span_ancestry_fields(&mut map_serializer, span)?;
+
+            map_serializer.end()?;
+            buffer.write_all(b"\n")
+        };
+
+        if format(&mut buffer).is_ok() {
+            let _ = self.emit(&buffer);
+        }
+
+        BUFFER.with(|cell| *cell.borrow_mut() = buffer);
     }
 
     fn serialize_bunyan_core_fields(
@@ -59,23 +328,50 @@ impl<W: MakeWriter + 'static> BunyanFormattingLayer<W> {
         message: &str,
         level: &Level,
     ) -> Result<(), std::io::Error> {
-        map_serializer.serialize_entry("msg", &message)?;
-        map_serializer.serialize_entry("level", &format_log_level(level))?;
-        map_serializer.serialize_entry("time", &chrono::Utc::now().to_rfc3339())?;
+        map_serializer.serialize_entry("v", &0)?;
+        map_serializer.serialize_entry("name", &self.name)?;
+        map_serializer.serialize_entry("hostname", &self.hostname)?;
+        map_serializer.serialize_entry("pid", &self.pid)?;
+        map_serializer.serialize_entry(&self.message_field_name, &message)?;
+        if self.use_bunyan_level_codes {
+            map_serializer.serialize_entry(&self.level_field_name, &bunyan_level_code(level))?;
+        } else {
+            map_serializer.serialize_entry(&self.level_field_name, &format_log_level(level))?;
+        }
+        match &self.timestamp_format {
+            TimestampFormat::Rfc3339 => {
+                map_serializer
+                    .serialize_entry(&self.time_field_name, &chrono::Utc::now().to_rfc3339())?;
+            }
+            TimestampFormat::UnixSeconds => {
+                map_serializer
+                    .serialize_entry(&self.time_field_name, &chrono::Utc::now().timestamp())?;
+            }
+            TimestampFormat::UnixMillis => {
+                map_serializer.serialize_entry(
+                    &self.time_field_name,
+                    &chrono::Utc::now().timestamp_millis(),
+                )?;
+            }
+            TimestampFormat::Custom(pattern) => {
+                map_serializer.serialize_entry(
+                    &self.time_field_name,
+                    &chrono::Utc::now().format(pattern).to_string(),
+                )?;
+            }
+        }
         Ok(())
     }
 
-    /// Given an in-memory buffer holding a complete serialised record, flush it to the writer
-    /// returned by self.make_writer.
-    ///
-    /// We add a trailing new-line at the end of the serialised record.
+    /// Given an in-memory buffer holding a complete serialised record (including its trailing
+    /// new-line), flush it to the writer returned by self.make_writer.
     ///
     /// If we write directly to the writer returned by self.make_writer in more than one go
     /// we can end up with broken/incoherent bits and pieces of those records when
-    /// running multi-threaded/concurrent programs.
-    fn emit(&self, mut buffer: Vec<u8>) -> Result<(), std::io::Error> {
-        buffer.write_all(b"\n")?;
-        self.make_writer.make_writer().write_all(&buffer)
+    /// running multi-threaded/concurrent programs, so callers must hand us a buffer that
+    /// already contains everything that needs to be written.
+    fn emit(&self, buffer: &[u8]) -> Result<(), std::io::Error> {
+        self.make_writer.make_writer().write_all(buffer)
     }
 }
 
@@ -113,11 +409,16 @@ where
         let mut event_visitor = JsonStorage::default();
         event.record(&mut event_visitor);
 
-        // Opting for a closure to use the ? operator and get more linear code.
-        let format = || {
-            let mut buffer = Vec::new();
+        // Take the thread-local buffer out of its `RefCell` rather than holding a `borrow_mut()`
+        // across formatting: formatting can itself emit a `tracing` event (e.g. the reserved-field
+        // `debug!` in `serialize_span_ancestry_fields`), which would re-enter `on_event` on the
+        // same thread and panic with `BorrowMutError` if the borrow were still live.
+        let mut buffer = BUFFER.with(|buffer| std::mem::take(&mut *buffer.borrow_mut()));
+        buffer.clear();
 
-            let mut serializer = serde_json::Serializer::new(&mut buffer);
+        // Opting for a closure to use the ? operator and get more linear code.
+        let format = |buffer: &mut Vec<u8>| -> Result<(), std::io::Error> {
+            let mut serializer = serde_json::Serializer::new(&mut *buffer);
             let mut map_serializer = serializer.serialize_map(None)?;
 
             let message = format_event_message(event, &event_visitor);
@@ -142,34 +443,59 @@ where
             for (key, value) in event_visitor
                 .values()
                 .iter()
-                .filter(|(&key, _)| key != "message" && !RESERVED_FIELDS.contains(&key))
+                .filter(|(&key, _)| key != "message" && !self.is_reserved_field(key))
             {
                 map_serializer.serialize_entry(key, value)?;
             }
 
-            // Add all the fields from the current span, if we have one.
+            // Add all the fields from the span ancestry, walking from the root span down to
+            // the current one so that fields on inner spans override those of their
+            // ancestors when keys collide.
             if let Some(span) = &current_span {
-                let extensions = span.extensions();
-                if let Some(visitor) = extensions.get::<JsonStorage>() {
-                    for (key, value) in visitor.values() {
-                        if !RESERVED_FIELDS.contains(key) {
-                            map_serializer.serialize_entry(key, value)?;
-                        } else {
-                            tracing::debug!(
-                                "{} is a reserved field in the bunyan log format. Skipping it.",
-                                key
-                            );
-                        }
-                    }
-                }
+                self.serialize_span_ancestry_fields(&mut map_serializer, span)?;
             }
             map_serializer.end()?;
-            Ok(buffer)
+            buffer.write_all(b"\n")
         };
 
-        let result: std::io::Result<Vec<u8>> = format();
-        if let Ok(formatted) = result {
-            let _ = self.emit(formatted);
+        if format(&mut buffer).is_ok() {
+            let _ = self.emit(&buffer);
         }
+
+        BUFFER.with(|cell| *cell.borrow_mut() = buffer);
+    }
+
+    fn on_new_span(
+        &self,
+        _attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        if self.span_events.contains(BunyanFmtSpan::CLOSE) {
+            span.extensions_mut()
+                .insert(SpanTiming(std::time::Instant::now()));
+        }
+
+        if self.span_events.contains(BunyanFmtSpan::NEW) {
+            let message = format!("[{} - START]", span.metadata().name());
+            self.emit_span_lifecycle_record(&span, &message, None);
+        }
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: Context<'_, S>) {
+        if !self.span_events.contains(BunyanFmtSpan::CLOSE) {
+            return;
+        }
+
+        let span = ctx.span(&id).expect("Span not found, this is a bug");
+        let elapsed_milliseconds = span
+            .extensions()
+            .get::<SpanTiming>()
+            .map(|timing| timing.0.elapsed().as_millis())
+            .unwrap_or_default();
+
+        let message = format!("[{} - END]", span.metadata().name());
+        self.emit_span_lifecycle_record(&span, &message, Some(elapsed_milliseconds));
     }
 }